@@ -0,0 +1,140 @@
+// Copyright 2022 Matthew James Kraai
+
+// This file is part of books.
+
+// books is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// books is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public
+// License along with books.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+//! Reads title and author metadata out of an EPUB's OPF package
+//! document.
+
+use std::{
+    fs::File,
+    io::Read,
+    path::Path,
+};
+
+/// An author listed in an EPUB's metadata.
+pub struct Author {
+    /// The author's name, as written by the publisher.
+    pub name: String,
+    /// The author's "file-as" sort name, if the EPUB3 metadata
+    /// refines it.
+    pub sort_name: Option<String>,
+}
+
+/// The title and authors read out of an EPUB.
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<Author>,
+}
+
+/// Reads `path` as a ZIP archive, follows its `container.xml` to the
+/// OPF package document, and returns the title and authors found
+/// there.
+pub fn read(path: &Path) -> Result<Metadata, String> {
+    let file =
+        File::open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+
+    let container = read_entry(&mut archive, path, "META-INF/container.xml")?;
+    let container_doc = roxmltree::Document::parse(&container)
+        .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+    let rootfile = container_doc
+        .descendants()
+        .find(|n| n.has_tag_name("rootfile"))
+        .and_then(|n| n.attribute("full-path"))
+        .ok_or_else(|| format!("{}: container.xml has no rootfile", path.display()))?
+        .to_string();
+
+    let opf = read_entry(&mut archive, path, &rootfile)?;
+    let opf_doc = roxmltree::Document::parse(&opf)
+        .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+    let package = opf_doc.root_element();
+    let is_epub3 = package
+        .attribute("version")
+        .map_or(false, |version| version.starts_with('3'));
+
+    let title = package
+        .descendants()
+        .find(|n| n.has_tag_name("title"))
+        .and_then(|n| n.text())
+        .ok_or_else(|| format!("{}: OPF has no dc:title", path.display()))?
+        .to_string();
+
+    let authors = package
+        .descendants()
+        .filter(|n| n.has_tag_name("creator"))
+        .filter_map(|creator| {
+            let name = creator.text()?.to_string();
+            if is_epub3 {
+                let id = creator.attribute("id");
+                let role = id.and_then(|id| find_refinement(&package, id, "role"));
+                if matches!(role.as_deref(), Some(role) if role != "aut") {
+                    return None;
+                }
+                let sort_name = id.and_then(|id| find_refinement(&package, id, "file-as"));
+                Some(Author { name, sort_name })
+            } else {
+                let role = creator.attribute((OPF_NAMESPACE, "role"));
+                if matches!(role, Some(role) if role != "aut") {
+                    return None;
+                }
+                Some(Author {
+                    name,
+                    sort_name: None,
+                })
+            }
+        })
+        .collect();
+
+    Ok(Metadata { title, authors })
+}
+
+const OPF_NAMESPACE: &str = "http://www.idpf.org/2007/opf";
+
+/// Finds the `<meta refines="#ID" property="PROPERTY">` element
+/// refining the element with the given `id` and returns its text.
+fn find_refinement<'a, 'input>(
+    package: &roxmltree::Node<'a, 'input>,
+    id: &str,
+    property: &str,
+) -> Option<String> {
+    let refines = format!("#{}", id);
+    package
+        .descendants()
+        .find(|n| {
+            n.has_tag_name("meta")
+                && n.attribute("refines") == Some(refines.as_str())
+                && n.attribute("property") == Some(property)
+        })
+        .and_then(|n| n.text())
+        .map(str::to_string)
+}
+
+fn read_entry(
+    archive: &mut zip::ZipArchive<File>,
+    path: &Path,
+    name: &str,
+) -> Result<String, String> {
+    let mut entry = archive
+        .by_name(name)
+        .map_err(|e| format!("{}: cannot read {}: {}", path.display(), name, e))?;
+    let mut contents = String::new();
+    entry
+        .read_to_string(&mut contents)
+        .map_err(|e| format!("{}: cannot read {}: {}", path.display(), name, e))?;
+    Ok(contents)
+}
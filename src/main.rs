@@ -19,9 +19,12 @@
 use clap::Parser;
 use directories::ProjectDirs;
 use pager::Pager;
-use rusqlite::{Connection, OptionalExtension};
+use rusqlite::{Connection, OptionalExtension, Transaction};
 use std::{fs::DirBuilder, os::unix::fs::DirBuilderExt, process};
 
+mod epub;
+mod migrations;
+
 #[derive(Parser)]
 enum Options {
     /// Add a book
@@ -34,12 +37,32 @@ enum Options {
         /// URL of the book
         #[clap(long)]
         url: Option<String>,
+        /// Series the book belongs to
+        #[clap(long, requires = "series_index")]
+        series: Option<String>,
+        /// Book's position within its series
+        #[clap(long, requires = "series")]
+        series_index: Option<i64>,
+    },
+    /// Check the database for integrity problems
+    Check {
+        /// Clear invalid URLs instead of just reporting them
+        #[clap(long)]
+        fix: bool,
     },
     /// Finish reading a book
     Finish {
         /// Title of the book
         title: String,
     },
+    /// List known genres and how many books are tagged with each
+    Genres,
+    /// Import books from EPUB files
+    Import {
+        /// EPUB files to import
+        #[clap(name = "FILE", required = true)]
+        files: Vec<String>,
+    },
     /// List books
     #[clap(name = "ls")]
     List {
@@ -52,6 +75,15 @@ enum Options {
         /// List books with no URL
         #[clap(long)]
         without_url: bool,
+        /// List books in a series instead, ordered by position
+        #[clap(long, value_name = "NAME")]
+        series: Option<String>,
+        /// List titles grouped by author, ordered by sort name
+        #[clap(long)]
+        by_author: bool,
+        /// List books tagged with a genre instead
+        #[clap(long, value_name = "NAME")]
+        genre: Option<String>,
     },
     /// Change a book's title
     #[clap(name = "mv")]
@@ -61,6 +93,16 @@ enum Options {
         /// New title of the book
         new_title: String,
     },
+    /// Set a book's series and position within it
+    #[clap(name = "set-series")]
+    SetSeries {
+        /// Title of the book
+        title: String,
+        /// Series the book belongs to
+        series: String,
+        /// Book's position within its series
+        series_index: i64,
+    },
     /// Set a book's URL
     #[clap(name = "set-url")]
     SetUrl {
@@ -78,6 +120,20 @@ enum Options {
         /// Title of the book
         title: String,
     },
+    /// Tag a book with a genre
+    Tag {
+        /// Title of the book
+        title: String,
+        /// Genre to tag the book with
+        genre: String,
+    },
+    /// Remove a genre from a book
+    Untag {
+        /// Title of the book
+        title: String,
+        /// Genre to remove from the book
+        genre: String,
+    },
 }
 
 macro_rules! die {
@@ -91,6 +147,93 @@ macro_rules! die {
     });
 }
 
+/// Derives a sortable "file-as" form of `name` by moving its last
+/// whitespace-delimited token to the front, e.g. "Isaac Asimov"
+/// becomes "Asimov, Isaac". This is a simple heuristic, not a name
+/// parser: it mishandles multi-word surnames, e.g. "Ursula K. Le
+/// Guin" becomes "Guin, Ursula K. Le" rather than "Le Guin, Ursula
+/// K.". `Options::Import` avoids this when the EPUB3 metadata refines
+/// an author with `file-as`, taking that sort name as given; other
+/// imported authors are derived here as well.
+fn derive_sort_name(name: &str) -> String {
+    match name.rsplit_once(char::is_whitespace) {
+        Some((rest, last)) => format!("{}, {}", last, rest),
+        None => name.to_string(),
+    }
+}
+
+/// Reports whether `url` has a scheme and a non-empty remainder,
+/// e.g. `https://example.com`.
+fn is_valid_url(url: &str) -> bool {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+                && !rest.is_empty()
+        }
+        None => false,
+    }
+}
+
+/// Inserts a book and its authors within `transaction`, as used by
+/// both `Options::Add` and `Options::Import`. An author who already
+/// has a sort name stored keeps it; otherwise the sort name is taken
+/// from the pair's second element if given, falling back to
+/// `derive_sort_name`.
+fn add_book(
+    transaction: &Transaction,
+    title: &str,
+    authors: &[(String, Option<String>)],
+    url: Option<&str>,
+    series: Option<(&str, i64)>,
+) {
+    if let Some(url) = url {
+        let mut statement = transaction
+            .prepare("INSERT INTO book (title, url) VALUES (?, ?)")
+            .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+        statement
+            .execute([title, url])
+            .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+    } else {
+        let mut statement = transaction
+            .prepare("INSERT INTO book (title) VALUES (?)")
+            .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+        statement
+            .execute([title])
+            .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+    }
+    for (author, sort_name) in authors {
+        let existing_sort_name = transaction
+            .query_row(
+                "SELECT sort_name FROM author WHERE author = ?",
+                [author],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+            .flatten();
+        let sort_name = existing_sort_name
+            .or_else(|| sort_name.clone())
+            .unwrap_or_else(|| derive_sort_name(author));
+        let mut statement = transaction
+            .prepare("INSERT INTO author VALUES (?, ?, ?)")
+            .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+        statement
+            .execute(rusqlite::params![title, author, sort_name])
+            .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+    }
+    if let Some((series, series_index)) = series {
+        transaction
+            .execute(
+                "UPDATE book SET series = ?, series_index = ? WHERE title = ?",
+                rusqlite::params![series, series_index, title],
+            )
+            .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+    }
+}
+
 fn main() {
     let options = Options::parse();
     let project_dirs = ProjectDirs::from("org.ftbfs", "", "books")
@@ -104,9 +247,7 @@ fn main() {
     let database = data_dir.join("database.sqlite3");
     let mut connection = Connection::open(&database)
         .unwrap_or_else(|e| die!("cannot open {}: {}", database.display(), e));
-    connection
-        .execute_batch(include_str!("schema.sql"))
-        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+    migrations::run(&mut connection).unwrap_or_else(|e| die!("cannot apply migrations: {}", e));
     connection
         .pragma_update(None, "FOREIGN_KEYS", 1)
         .unwrap_or_else(|e| die!("cannot enable foreign key constraints: {}", e));
@@ -115,38 +256,104 @@ fn main() {
             title,
             authors,
             url,
+            series,
+            series_index,
         } => {
             let transaction = connection
                 .transaction()
                 .unwrap_or_else(|e| die!("cannot create transaction: {}", e));
+            let series = series.as_deref().zip(series_index);
+            let authors = authors
+                .into_iter()
+                .map(|author| (author, None))
+                .collect::<Vec<_>>();
+            add_book(&transaction, &title, &authors, url.as_deref(), series);
+            transaction
+                .commit()
+                .unwrap_or_else(|e| die!("cannot commit transaction: {}", e));
+        }
+        Options::Check { fix } => {
+            let mut invalid_urls = Vec::new();
             {
-                if let Some(url) = url {
-                    let mut statement = transaction
-                        .prepare("INSERT INTO book (title, url) VALUES (?, ?)")
-                        .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
-                    statement
-                        .execute([&title, &url])
+                let mut statement = connection
+                    .prepare("SELECT title, url FROM book WHERE url IS NOT NULL")
+                    .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+                let mut rows = statement
+                    .query([])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let title: String = row
+                        .get(0)
                         .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
-                } else {
-                    let mut statement = transaction
-                        .prepare("INSERT INTO book (title) VALUES (?)")
-                        .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
-                    statement
-                        .execute([&title])
+                    let url: String = row
+                        .get(1)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    if !is_valid_url(&url) {
+                        println!("invalid URL: {} ({})", title, url);
+                        invalid_urls.push(title);
+                    }
+                }
+            }
+            {
+                let mut statement = connection
+                    .prepare(
+                        "SELECT GROUP_CONCAT(title, ', ') FROM book \
+                         GROUP BY title COLLATE NOCASE HAVING COUNT(*) > 1",
+                    )
+                    .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+                let mut rows = statement
+                    .query([])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let titles: String = row
+                        .get(0)
                         .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    println!("duplicate title: {}", titles);
                 }
             }
-            for author in authors {
-                let mut statement = transaction
-                    .prepare("INSERT INTO author VALUES (?, ?)")
+            {
+                let mut statement = connection
+                    .prepare(
+                        "SELECT url, COUNT(*) FROM book WHERE url IS NOT NULL \
+                         GROUP BY url HAVING COUNT(*) > 1",
+                    )
                     .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
-                statement
-                    .execute([&title, &author])
+                let mut rows = statement
+                    .query([])
                     .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let url: String = row
+                        .get(0)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    let count: i64 = row
+                        .get(1)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    println!("duplicate URL: {} ({} books)", url, count);
+                }
+            }
+            if fix && !invalid_urls.is_empty() {
+                let transaction = connection
+                    .transaction()
+                    .unwrap_or_else(|e| die!("cannot create transaction: {}", e));
+                for title in &invalid_urls {
+                    transaction
+                        .execute("UPDATE book SET url = NULL WHERE title = ?", [title])
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    println!("cleared invalid URL: {}", title);
+                }
+                transaction
+                    .commit()
+                    .unwrap_or_else(|e| die!("cannot commit transaction: {}", e));
             }
-            transaction
-                .commit()
-                .unwrap_or_else(|e| die!("cannot commit transaction: {}", e));
         }
         Options::Finish { title } => {
             if connection
@@ -160,24 +367,14 @@ fn main() {
                 die!("not found: {}", title);
             }
         }
-        Options::List {
-            finished,
-            started,
-            without_url,
-        } => {
+        Options::Genres => {
             Pager::new().setup();
-            let statement = if finished {
-                "SELECT title FROM book WHERE end_date IS NOT NULL ORDER BY end_date"
-            } else if started {
-                "SELECT title FROM book WHERE start_date IS NOT NULL AND end_date IS NULL ORDER BY title"
-            } else if without_url {
-                "SELECT title FROM book WHERE url IS NULL ORDER BY title"
-            } else {
-                "SELECT title FROM book WHERE start_date IS NULL ORDER BY title"
-            };
             let mut statement = connection
-                .prepare(statement)
-                .unwrap_or_else(|e| die!("cannot prepare statement \"{}\": {}", statement, e));
+                .prepare(
+                    "SELECT genre.name, COUNT(book_genre.title) FROM genre LEFT JOIN book_genre \
+                     ON genre.name = book_genre.genre GROUP BY genre.name ORDER BY genre.name",
+                )
+                .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
             let mut rows = statement
                 .query([])
                 .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
@@ -185,10 +382,128 @@ fn main() {
                 .next()
                 .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
             {
-                let title: String = row
+                let name: String = row
                     .get(0)
                     .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
-                println!("{}", title);
+                let count: i64 = row
+                    .get(1)
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                println!("{} ({})", name, count);
+            }
+        }
+        Options::Import { files } => {
+            for file in files {
+                let metadata = epub::read(std::path::Path::new(&file))
+                    .unwrap_or_else(|e| die!("{}", e));
+                let authors = metadata
+                    .authors
+                    .into_iter()
+                    .map(|author| (author.name, author.sort_name))
+                    .collect::<Vec<_>>();
+                let transaction = connection
+                    .transaction()
+                    .unwrap_or_else(|e| die!("cannot create transaction: {}", e));
+                add_book(&transaction, &metadata.title, &authors, None, None);
+                transaction
+                    .commit()
+                    .unwrap_or_else(|e| die!("cannot commit transaction: {}", e));
+            }
+        }
+        Options::List {
+            finished,
+            started,
+            without_url,
+            series,
+            by_author,
+            genre,
+        } => {
+            Pager::new().setup();
+            if by_author {
+                let mut statement = connection
+                    .prepare(
+                        "SELECT author, title FROM author JOIN book USING (title) \
+                         ORDER BY sort_name, title",
+                    )
+                    .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+                let mut rows = statement
+                    .query([])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                let mut last_author: Option<String> = None;
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let author: String = row
+                        .get(0)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    let title: String = row
+                        .get(1)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    if last_author.as_ref() != Some(&author) {
+                        println!("{}", author);
+                        last_author = Some(author);
+                    }
+                    println!("    {}", title);
+                }
+            } else if let Some(series) = series {
+                let mut statement = connection
+                    .prepare("SELECT title FROM book WHERE series = ? ORDER BY series_index")
+                    .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+                let mut rows = statement
+                    .query([&series])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let title: String = row
+                        .get(0)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    println!("{}", title);
+                }
+            } else if let Some(genre) = genre {
+                let mut statement = connection
+                    .prepare(
+                        "SELECT title FROM book_genre WHERE genre = ? ORDER BY title",
+                    )
+                    .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+                let mut rows = statement
+                    .query([&genre])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let title: String = row
+                        .get(0)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    println!("{}", title);
+                }
+            } else {
+                let statement = if finished {
+                    "SELECT title FROM book WHERE end_date IS NOT NULL ORDER BY end_date"
+                } else if started {
+                    "SELECT title FROM book WHERE start_date IS NOT NULL AND end_date IS NULL ORDER BY title"
+                } else if without_url {
+                    "SELECT title FROM book WHERE url IS NULL ORDER BY title"
+                } else {
+                    "SELECT title FROM book WHERE start_date IS NULL ORDER BY title"
+                };
+                let mut statement = connection
+                    .prepare(statement)
+                    .unwrap_or_else(|e| die!("cannot prepare statement \"{}\": {}", statement, e));
+                let mut rows = statement
+                    .query([])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let title: String = row
+                        .get(0)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    println!("{}", title);
+                }
             }
         }
         Options::Rename {
@@ -206,6 +521,22 @@ fn main() {
                 die!("not found: {}", old_title);
             }
         }
+        Options::SetSeries {
+            title,
+            series,
+            series_index,
+        } => {
+            if connection
+                .execute(
+                    "UPDATE book SET series = ?, series_index = ? WHERE title = ?",
+                    rusqlite::params![series, series_index, title],
+                )
+                .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                != 1
+            {
+                die!("not found: {}", title);
+            }
+        }
         Options::SetUrl { title, url } => {
             if connection
                 .execute("UPDATE book SET url = ? WHERE title = ?", [&url, &title])
@@ -217,15 +548,19 @@ fn main() {
         }
         Options::Show { title } => {
             Pager::new().setup();
-            if let Some((url, start_date, end_date)) = connection
+            if let Some((url, start_date, end_date, series, series_index)) = connection
                 .query_row(
-                    "SELECT url, start_date, end_date FROM book WHERE title = ?",
+                    "SELECT url, start_date, end_date, series, series_index FROM book WHERE title = ?",
                     [&title],
                     |row| {
                         row.get(0).and_then(|url: Option<String>| {
                             row.get(1).and_then(|start_date: Option<String>| {
                                 row.get(2).and_then(|end_date: Option<String>| {
-                                    Ok((url, start_date, end_date))
+                                    row.get(3).and_then(|series: Option<String>| {
+                                        row.get(4).and_then(|series_index: Option<i64>| {
+                                            Ok((url, start_date, end_date, series, series_index))
+                                        })
+                                    })
                                 })
                             })
                         })
@@ -238,6 +573,12 @@ fn main() {
                 if let Some(url) = url {
                     println!("URL: {}", url);
                 }
+                if let Some(series) = series {
+                    match series_index {
+                        Some(series_index) => println!("Series: {} (#{})", series, series_index),
+                        None => println!("Series: {}", series),
+                    }
+                }
                 if let Some(start_date) = start_date {
                     println!("Started: {}", start_date);
                 }
@@ -261,6 +602,25 @@ fn main() {
                     authors.push(author);
                 }
                 println!("Authors: {}", authors.join(", "));
+                let mut genres = Vec::new();
+                let mut statement = connection
+                    .prepare("SELECT genre FROM book_genre WHERE title = ? ORDER BY genre")
+                    .unwrap_or_else(|e| die!("cannot prepare statement: {}", e));
+                let mut rows = statement
+                    .query([&title])
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                while let Some(row) = rows
+                    .next()
+                    .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                {
+                    let genre: String = row
+                        .get(0)
+                        .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+                    genres.push(genre);
+                }
+                if !genres.is_empty() {
+                    println!("Genres: {}", genres.join(", "));
+                }
             }
         }
         Options::Start { title } => {
@@ -275,5 +635,42 @@ fn main() {
                 die!("not found: {}", title);
             }
         }
+        Options::Tag { title, genre } => {
+            let transaction = connection
+                .transaction()
+                .unwrap_or_else(|e| die!("cannot create transaction: {}", e));
+            if !transaction
+                .query_row("SELECT 1 FROM book WHERE title = ?", [&title], |_| Ok(()))
+                .optional()
+                .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                .is_some()
+            {
+                die!("not found: {}", title);
+            }
+            transaction
+                .execute("INSERT OR IGNORE INTO genre (name) VALUES (?)", [&genre])
+                .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+            transaction
+                .execute(
+                    "INSERT OR IGNORE INTO book_genre (title, genre) VALUES (?, ?)",
+                    [&title, &genre],
+                )
+                .unwrap_or_else(|e| die!("cannot execute statement: {}", e));
+            transaction
+                .commit()
+                .unwrap_or_else(|e| die!("cannot commit transaction: {}", e));
+        }
+        Options::Untag { title, genre } => {
+            if connection
+                .execute(
+                    "DELETE FROM book_genre WHERE title = ? AND genre = ?",
+                    [&title, &genre],
+                )
+                .unwrap_or_else(|e| die!("cannot execute statement: {}", e))
+                != 1
+            {
+                die!("not tagged: {}", title);
+            }
+        }
     }
 }
@@ -0,0 +1,88 @@
+// Copyright 2022 Matthew James Kraai
+
+// This file is part of books.
+
+// books is free software: you can redistribute it and/or modify it
+// under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// books is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public
+// License along with books.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+//! Brings a database up to date by applying, in order, whichever of
+//! the SQL steps in `MIGRATIONS` it has not yet applied, tracking
+//! progress in a `schema_version` table.
+
+use rusqlite::Connection;
+
+/// The SQL run to reach each schema version, in order starting from
+/// version 1.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original book and author tables. These use IF NOT
+    // EXISTS because databases created by the old
+    // `include_str!("schema.sql")` startup logic already have them.
+    "
+    CREATE TABLE IF NOT EXISTS book (
+        title TEXT PRIMARY KEY,
+        url TEXT,
+        start_date TEXT,
+        end_date TEXT
+    );
+    CREATE TABLE IF NOT EXISTS author (
+        title TEXT NOT NULL REFERENCES book (title),
+        author TEXT NOT NULL,
+        PRIMARY KEY (title, author)
+    );
+    ",
+    // 2: series membership and reading order.
+    "
+    ALTER TABLE book ADD COLUMN series TEXT;
+    ALTER TABLE book ADD COLUMN series_index INTEGER;
+    ",
+    // 3: a sortable "file-as" form of each author's name.
+    "
+    ALTER TABLE author ADD COLUMN sort_name TEXT;
+    ",
+    // 4: genre tagging.
+    "
+    CREATE TABLE genre (
+        name TEXT PRIMARY KEY
+    );
+    CREATE TABLE book_genre (
+        title TEXT NOT NULL REFERENCES book (title),
+        genre TEXT NOT NULL REFERENCES genre (name),
+        PRIMARY KEY (title, genre)
+    );
+    ",
+];
+
+/// Applies any migrations in `MIGRATIONS` that `connection` has not
+/// yet applied.
+pub fn run(connection: &mut Connection) -> Result<(), rusqlite::Error> {
+    connection
+        .execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    let count: i64 =
+        connection.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))?;
+    if count == 0 {
+        connection.execute("INSERT INTO schema_version (version) VALUES (0)", [])?;
+    }
+    let version: i64 =
+        connection.query_row("SELECT version FROM schema_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate() {
+        if (i as i64) < version {
+            continue;
+        }
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.execute("UPDATE schema_version SET version = ?", [i as i64 + 1])?;
+        transaction.commit()?;
+    }
+    Ok(())
+}